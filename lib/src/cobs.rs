@@ -0,0 +1,115 @@
+//! Consistent Overhead Byte Stuffing for the serial protocol.
+//!
+//! Every frame is encoded so that the payload contains no `0x00` bytes, letting a single `0x00`
+//! delimiter mark frame boundaries. Either side can resynchronise after a glitch by scanning to the
+//! next delimiter.
+
+/// Error returned by [`decode`] when the encoded frame is malformed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A group claimed more bytes than the frame actually contained.
+    Truncated,
+    /// The decoded payload did not fit in the supplied `output` buffer.
+    Overflow,
+}
+
+/// Upper bound on the encoded length of a `len`-byte payload, including the trailing delimiter.
+pub fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 2
+}
+
+/// COBS-encode `input` into `output`, appending the `0x00` frame delimiter, and return the number
+/// of bytes written. `output` must be at least [`max_encoded_len`] bytes long.
+pub fn encode(input: &[u8], output: &mut [u8]) -> usize {
+    let mut code_index = 0;
+    let mut write = 1;
+    let mut code = 1u8;
+    for &byte in input {
+        if byte != 0 {
+            output[write] = byte;
+            write += 1;
+            code += 1;
+            if code == 0xFF {
+                output[code_index] = code;
+                code_index = write;
+                write += 1;
+                code = 1;
+            }
+        } else {
+            output[code_index] = code;
+            code_index = write;
+            write += 1;
+            code = 1;
+        }
+    }
+    output[code_index] = code;
+    output[write] = 0x00;
+    write + 1
+}
+
+/// Decode a COBS frame from `input` into `output`, stopping at the `0x00` delimiter, and return the
+/// number of decoded bytes.
+pub fn decode(input: &[u8], output: &mut [u8]) -> Result<usize, DecodeError> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < input.len() {
+        let code = input[read];
+        if code == 0 {
+            return Ok(write);
+        }
+        read += 1;
+        for _ in 1..code {
+            let byte = *input.get(read).ok_or(DecodeError::Truncated)?;
+            *output.get_mut(write).ok_or(DecodeError::Overflow)? = byte;
+            write += 1;
+            read += 1;
+        }
+        if code != 0xFF && read < input.len() && input[read] != 0 {
+            *output.get_mut(write).ok_or(DecodeError::Overflow)? = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) {
+        let mut encoded = [0u8; 1024];
+        let n = encode(input, &mut encoded);
+        assert_eq!(encoded[n - 1], 0, "frame must end with the delimiter");
+        let mut decoded = [0u8; 1024];
+        let m = decode(&encoded[..n], &mut decoded).unwrap();
+        assert_eq!(&decoded[..m], input);
+    }
+
+    #[test]
+    fn round_trips_empty_input() {
+        round_trip(&[]);
+    }
+
+    #[test]
+    fn round_trips_zero_bytes() {
+        round_trip(&[0, 0, 0]);
+        round_trip(&[1, 0, 2, 0, 0, 3]);
+    }
+
+    #[test]
+    fn round_trips_long_zero_free_run() {
+        // A full 254-byte run with no zero exercises the 0xFF code-byte path.
+        round_trip(&[0xAB; 254]);
+        let mut longer = [0x01u8; 300];
+        longer[150] = 0;
+        round_trip(&longer);
+    }
+
+    #[test]
+    fn decode_rejects_overflowing_output() {
+        let mut encoded = [0u8; 64];
+        let n = encode(&[1, 2, 3, 4, 5], &mut encoded);
+        let mut tiny = [0u8; 2];
+        assert_eq!(decode(&encoded[..n], &mut tiny), Err(DecodeError::Overflow));
+    }
+}
@@ -0,0 +1,41 @@
+//! Deframing shared by the host and bootloader serial loops.
+//!
+//! Both sides receive a byte at a time from different sources (an [`mpsc`] channel on the host, the
+//! UEFI `Serial` protocol on the device), so the I/O loop stays per-side, but the accumulate-and-
+//! decode logic lives here so it is written — and capped — once.
+//!
+//! [`mpsc`]: https://doc.rust-lang.org/std/sync/mpsc/index.html
+
+use crate::cobs::{self, DecodeError};
+
+/// Accumulates received bytes into a single COBS frame, decoding it when the `0x00` delimiter
+/// arrives. Bytes beyond the scratch buffer's capacity are dropped so a line glitch or injected
+/// noise just resynchronises at the next delimiter instead of overflowing.
+pub struct FrameDecoder<'a> {
+    scratch: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> FrameDecoder<'a> {
+    /// Create a decoder buffering into `scratch`, which should be [`cobs::max_encoded_len`] of the
+    /// largest expected payload.
+    pub fn new(scratch: &'a mut [u8]) -> Self {
+        Self { scratch, len: 0 }
+    }
+
+    /// Feed one received byte. Returns `Ok(Some(n))` with the decoded length once a full frame is
+    /// assembled into `out`, `Ok(None)` while mid-frame, or `Err` on a malformed frame (after which
+    /// the decoder has reset to resynchronise on the next delimiter).
+    pub fn push(&mut self, byte: u8, out: &mut [u8]) -> Result<Option<usize>, DecodeError> {
+        if byte == 0 {
+            let decoded = cobs::decode(&self.scratch[..self.len], out);
+            self.len = 0;
+            return decoded.map(Some);
+        }
+        if self.len < self.scratch.len() {
+            self.scratch[self.len] = byte;
+            self.len += 1;
+        }
+        Ok(None)
+    }
+}
@@ -2,6 +2,9 @@
 
 use crc::{Crc, CRC_16_IBM_SDLC};
 
+pub mod cobs;
+pub mod frame;
+
 static X25: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
 
 pub static CHUNK_SIZE: usize = 256;
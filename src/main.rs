@@ -1,23 +1,78 @@
 use std::env::VarError;
 use std::fmt::Write;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use serialport::SerialPort;
 
-use trebuchet_lib::CHUNK_SIZE;
+use trebuchet_lib::{cobs, frame, CHUNK_SIZE};
 
 static SERIAL_TIMEOUT: Duration = Duration::from_micros(10);
 
+/// Backoff between attempts while waiting for the serial port to (re)appear.
+static PORT_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Bounded timeout used when re-opening the port after a mid-transfer disconnect.
+static RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of transmit attempts for a single chunk in [`send_chunk`], and of consecutive window
+/// rewinds on `NAK`/timeout in [`send_window`], before the sender returns `Err`.
+static MAX_RETRANSMITS: usize = 8;
+
+/// Upper bound on the decoded length of a control frame (`ACK`/`NAK`/`OK`/`RDY`) read by the host.
+static MAX_CONTROL_FRAME: usize = 64;
+
+/// Number of consecutive reconnect attempts (without further progress) before the transfer aborts.
+static MAX_RECONNECTS: usize = 8;
+
+/// Per-chunk acknowledgement returned by the bootloader.
+enum ChunkReply {
+    Ack,
+    Nak,
+}
+
+/// Why a frame read ended without delivering a frame.
+enum FrameError {
+    /// No byte arrived within the timeout; the link is still up.
+    Timeout,
+    /// The reader thread exited (port read error / disconnect) and closed the channel.
+    Disconnected,
+}
+
+/// Handle to the background serial reader thread and the channel it feeds.
+struct SerialReader {
+    rx: Receiver<u8>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl SerialReader {
+    /// Signal the reader thread to stop and wait for it to exit, releasing its cloned port.
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
 #[derive(Parser)]
 struct Cli {
     serial_port: String,
     serial_baud: u32,
     image_path: PathBuf,
+    /// Seconds to wait for the serial port to appear before giving up (0 = fail immediately).
+    #[arg(long, default_value_t = 0)]
+    wait_timeout: u64,
+    /// Number of chunks kept in flight at once (1 = stop-and-wait).
+    #[arg(long, default_value_t = 1)]
+    window: usize,
 }
 
 fn main() {
@@ -36,36 +91,30 @@ fn main() {
     let args = Cli::parse();
     let image = open_image_file(&args.image_path);
     let timeout = Some(Duration::from_millis(30));
-    let mut port = open_serial_port(&args.serial_port, args.serial_baud);
+    let mut port = open_serial_port(
+        &args.serial_port,
+        args.serial_baud,
+        Duration::from_secs(args.wait_timeout),
+    );
+    let mut reader = spawn_serial_reader(port.try_clone().expect("Failed to clone serial port."));
 
     info!("Waiting for RDY signal.");
-    wait_for_bytes(
-        port.as_mut(),
-        format!("RDY({})\n", CHUNK_SIZE).as_bytes(),
-        None,
-    )
-    .expect("Failed to receive RDY.");
+    wait_for_bytes(&reader.rx, format!("RDY({})\n", CHUNK_SIZE).as_bytes(), None)
+        .expect("Failed to receive RDY.");
 
-    let size = &(image.len() as u64).to_be_bytes();
+    // The header carries the image size followed by the expected whole-image CRC so the device can
+    // verify the assembled image and fall back when it doesn't match.
+    let mut header = (image.len() as u64).to_be_bytes().to_vec();
+    header.extend_from_slice(&trebuchet_lib::checksum(&image).to_be_bytes());
     debug!("Transmitting image size.");
-    port.write_all(size)
+    send_chunk(port.as_mut(), &reader.rx, 0, &header, timeout)
         .expect("Failed to transmit image size.");
 
-    for i in 0..size.chunks(CHUNK_SIZE).len() {
-        wait_for_bytes(port.as_mut(), format!("ACK({})\n", i).as_bytes(), timeout)
-            .expect("Timed out waiting for image size ACK.");
-        trace!("ACK({})\n", i);
-    }
-
-    let size_checksum = trebuchet_lib::checksum(size);
-    debug!("Waiting for image size OK({}).", size_checksum);
-    wait_for_bytes(
-        port.as_mut(),
-        format!("OK({})\n", size_checksum).as_bytes(),
-        timeout,
-    )
-    .expect("Timed out waiting for image size OK.");
-    trace!("OK({})\n", size_checksum);
+    let header_checksum = trebuchet_lib::checksum(&header);
+    debug!("Waiting for image size OK({}).", header_checksum);
+    wait_for_bytes(&reader.rx, format!("OK({})\n", header_checksum).as_bytes(), timeout)
+        .expect("Timed out waiting for image size OK.");
+    trace!("OK({})\n", header_checksum);
 
     info!("Transmitting image.");
     let progress = multi.add(ProgressBar::new(image.len() as u64));
@@ -77,15 +126,41 @@ fn main() {
                 { write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap() })
         .progress_chars("#>-"));
 
-    for (i, chunk) in image.chunks(CHUNK_SIZE).enumerate() {
-        loop {
-            let _ = port.write_all(chunk);
-            if wait_for_bytes(port.as_mut(), format!("ACK({})\n", i).as_bytes(), timeout).is_ok() {
-                trace!("ACK({})\n", i);
-                break;
-            }
+    let chunks: Vec<&[u8]> = image.chunks(CHUNK_SIZE).collect();
+    let mut base = 0;
+    let mut last_base = 0;
+    let mut reconnects = 0;
+    while base < chunks.len() {
+        if send_window(
+            port.as_mut(),
+            &reader.rx,
+            &chunks,
+            &mut base,
+            args.window,
+            timeout,
+            &progress,
+        )
+        .is_ok()
+        {
+            continue;
+        }
+
+        // Reset the reconnect budget whenever we made progress before the link dropped.
+        if base > last_base {
+            reconnects = 0;
+            last_base = base;
         }
-        progress.inc(CHUNK_SIZE as u64);
+        reconnects += 1;
+        assert!(
+            reconnects <= MAX_RECONNECTS,
+            "Transfer aborted at chunk {} after {} reconnect attempts.",
+            base,
+            MAX_RECONNECTS
+        );
+
+        // Re-open the port and resume from the oldest unacked chunk.
+        warn!("Serial link lost; reconnecting to resume at chunk {}.", base);
+        (port, reader) = reconnect(&args, reader);
     }
     progress.finish();
     multi.remove(&progress);
@@ -93,7 +168,7 @@ fn main() {
     let image_checksum = trebuchet_lib::checksum(&image);
     debug!("Waiting for image OK({}).", image_checksum);
     wait_for_bytes(
-        port.as_mut(),
+        &reader.rx,
         format!("OK({})\n", image_checksum).as_bytes(),
         Some(Duration::from_millis(20)),
     )
@@ -102,11 +177,9 @@ fn main() {
 
     info!("Transmission complete.");
 
-    loop {
-        let mut c = 0u8;
-        if port.read(std::slice::from_mut(&mut c)).is_ok() {
-            print!("{}", c as char);
-        }
+    // Drain the same raw byte stream the transfer loop used to print whatever the booted image emits.
+    while let Ok(c) = reader.rx.recv() {
+        print!("{}", c as char);
     }
 }
 
@@ -114,37 +187,242 @@ fn open_image_file(path: &Path) -> Vec<u8> {
     std::fs::read(path).expect("Failed to read image file")
 }
 
-fn open_serial_port(port: &str, baud: u32) -> Box<dyn SerialPort> {
-    serialport::new(port, baud)
-        .timeout(SERIAL_TIMEOUT)
-        .open()
-        .expect("Failed to open serial port")
+/// Open the serial port, polling [`serialport::available_ports`] and retrying on a bounded backoff
+/// until the device node appears or `wait_timeout` elapses. A zero `wait_timeout` fails immediately
+/// if the port is not already present.
+fn open_serial_port(port: &str, baud: u32, wait_timeout: Duration) -> Box<dyn SerialPort> {
+    let deadline = Instant::now() + wait_timeout;
+    loop {
+        if port_available(port) {
+            if let Ok(opened) = serialport::new(port, baud).timeout(SERIAL_TIMEOUT).open() {
+                return opened;
+            }
+        }
+        if Instant::now() >= deadline {
+            panic!("Failed to open serial port {} within the wait timeout.", port);
+        }
+        debug!("Waiting for serial port {} to appear.", port);
+        thread::sleep(PORT_BACKOFF);
+    }
+}
+
+/// Whether `name` is currently enumerated among the system's serial ports.
+fn port_available(name: &str) -> bool {
+    serialport::available_ports()
+        .map(|ports| ports.iter().any(|it| it.port_name == name))
+        .unwrap_or(false)
+}
+
+/// Re-open the port after a transient disconnect and respawn the reader thread, waiting a bounded
+/// time for the adapter to re-enumerate. The previous reader is stopped and joined first so its
+/// thread and cloned port don't leak.
+fn reconnect(args: &Cli, reader: SerialReader) -> (Box<dyn SerialPort>, SerialReader) {
+    reader.stop();
+    let port = open_serial_port(&args.serial_port, args.serial_baud, RECONNECT_TIMEOUT);
+    let reader = spawn_serial_reader(port.try_clone().expect("Failed to clone serial port."));
+    (port, reader)
 }
 
-fn wait_for_bytes(
+/// Spawn a dedicated reader thread that continuously drains the port into a channel, giving the
+/// main thread a single raw byte stream to match `ACK`/`NAK`/`OK` frames and image log output
+/// against without contending for the port between reads and the transfer state machine. The thread
+/// exits on a read error (the disconnect case), a closed channel, or an explicit
+/// [`SerialReader::stop`].
+fn spawn_serial_reader(mut port: Box<dyn SerialPort>) -> SerialReader {
+    let (tx, rx) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = Arc::clone(&stop);
+    let handle = thread::spawn(move || {
+        let mut c = 0u8;
+        while !thread_stop.load(Ordering::Relaxed) {
+            match port.read(std::slice::from_mut(&mut c)) {
+                Ok(0) => continue,
+                Ok(_) => {
+                    if tx.send(c).is_err() {
+                        break;
+                    }
+                }
+                // A read timeout just means no byte is ready yet; any other error is a disconnect.
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                Err(_) => break,
+            }
+        }
+    });
+    SerialReader { rx, stop, handle }
+}
+
+/// Build the COBS-encoded wire frame for `index`: a big-endian index header, the payload, and a
+/// trailing 16-bit X.25 CRC over both. The index lets the bootloader place retransmitted chunks at
+/// the right offset.
+fn encode_chunk_frame(index: usize, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (index as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    let crc = trebuchet_lib::checksum(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    let mut encoded = vec![0u8; cobs::max_encoded_len(frame.len())];
+    let n = cobs::encode(&frame, &mut encoded);
+    encoded.truncate(n);
+    encoded
+}
+
+/// Transmit a single chunk and wait for the bootloader to confirm receipt, retransmitting on
+/// `NAK(index)` or timeout and giving up after [`MAX_RETRANSMITS`] attempts. Used for one-off
+/// transfers such as the image size; bulk image transfer goes through [`send_window`].
+fn send_chunk(
     port: &mut dyn SerialPort,
-    bytes: &[u8],
+    rx: &Receiver<u8>,
+    index: usize,
+    payload: &[u8],
     timeout: Option<Duration>,
-) -> Result<(), usize> {
-    let mut remaining = timeout;
-    let mut i = 0;
-    while i < bytes.len() && (timeout.is_none() || remaining.is_some_and(|it| it > Duration::ZERO))
-    {
-        let mut c = 0u8;
-        if port.read(core::slice::from_mut(&mut c)).is_ok() {
-            if bytes[i] == c {
-                i += 1;
-            } else {
-                i = 0;
+) -> Result<(), ()> {
+    let encoded = encode_chunk_frame(index, payload);
+    for _ in 0..MAX_RETRANSMITS {
+        // A write error means the link dropped; surface it so the caller can reconnect.
+        if port.write_all(&encoded).is_err() {
+            return Err(());
+        }
+        match wait_for_ack_or_nak(rx, index, timeout) {
+            Ok(ChunkReply::Ack) => {
+                trace!("ACK({})\n", index);
+                return Ok(());
+            }
+            Ok(ChunkReply::Nak) => trace!("NAK({})\n", index),
+            // A dropped link can't recover by retransmitting; surface it for reconnect.
+            Err(FrameError::Disconnected) => return Err(()),
+            Err(FrameError::Timeout) => (),
+        }
+    }
+    warn!("Chunk {} failed after {} retransmit attempts.", index, MAX_RETRANSMITS);
+    Err(())
+}
+
+/// Go-back-N sliding-window transfer. Keeps up to `window` chunks in flight, advancing `base` (the
+/// oldest unacknowledged chunk) as cumulative `ACK(i)` frames arrive and rewinding to `base` on a
+/// timeout or `NAK`. Returns `Err` on a write error, a reader disconnect, or after
+/// [`MAX_RETRANSMITS`] consecutive rewinds, so the caller can reconnect and resume from the current
+/// `base`.
+fn send_window(
+    port: &mut dyn SerialPort,
+    rx: &Receiver<u8>,
+    chunks: &[&[u8]],
+    base: &mut usize,
+    window: usize,
+    timeout: Option<Duration>,
+    progress: &ProgressBar,
+) -> Result<(), ()> {
+    let total = chunks.len();
+    let mut next = *base;
+    // Consecutive rewinds without the window advancing; bounded like send_chunk's retransmits.
+    let mut stalls = 0;
+    while *base < total {
+        while next < total && next < *base + window {
+            if port.write_all(&encode_chunk_frame(next, chunks[next])).is_err() {
+                return Err(());
+            }
+            next += 1;
+        }
+        match read_chunk_reply(rx, timeout) {
+            Ok((ChunkReply::Ack, i)) if i + 1 > *base => {
+                trace!("ACK({})\n", i);
+                let acked = &chunks[*base..=i];
+                progress.inc(acked.iter().map(|chunk| chunk.len()).sum::<usize>() as u64);
+                *base = i + 1;
+                next = next.max(*base);
+                stalls = 0;
             }
-        } else if timeout.is_some() {
-            remaining = remaining.map(|it| it.saturating_sub(port.timeout()));
+            Ok((ChunkReply::Ack, _)) => (),
+            // A dropped link can't recover by rewinding; surface it for reconnect.
+            Err(FrameError::Disconnected) => return Err(()),
+            // A `NAK` or a bare timeout means rewind to the oldest unacked chunk and resend.
+            Ok((ChunkReply::Nak, i)) => {
+                trace!("NAK({})\n", i);
+                next = *base;
+                stalls += 1;
+                if stalls >= MAX_RETRANSMITS {
+                    return Err(());
+                }
+            }
+            Err(FrameError::Timeout) => {
+                next = *base;
+                stalls += 1;
+                if stalls >= MAX_RETRANSMITS {
+                    return Err(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Wait for a reply confirming `index`, treating any cumulative `ACK(i)` with `i >= index` as an
+/// acknowledgement and any `NAK(i)` with `i <= index` as a retransmit request.
+fn wait_for_ack_or_nak(
+    rx: &Receiver<u8>,
+    index: usize,
+    timeout: Option<Duration>,
+) -> Result<ChunkReply, FrameError> {
+    loop {
+        match read_chunk_reply(rx, timeout)? {
+            (ChunkReply::Ack, i) if i >= index => return Ok(ChunkReply::Ack),
+            (ChunkReply::Nak, i) if i <= index => return Ok(ChunkReply::Nak),
+            _ => continue,
+        }
+    }
+}
+
+/// Read the next `ACK(i)`/`NAK(i)` control frame and return the reply kind with its index,
+/// discarding any other frames.
+fn read_chunk_reply(
+    rx: &Receiver<u8>,
+    timeout: Option<Duration>,
+) -> Result<(ChunkReply, usize), FrameError> {
+    loop {
+        let frame = read_frame(rx, timeout)?;
+        if let Some(i) = parse_reply(&frame, b"ACK(") {
+            return Ok((ChunkReply::Ack, i));
+        }
+        if let Some(i) = parse_reply(&frame, b"NAK(") {
+            return Ok((ChunkReply::Nak, i));
         }
     }
+}
+
+/// Parse the index out of a `PREFIX<index>)\n` control frame.
+fn parse_reply(frame: &[u8], prefix: &[u8]) -> Option<usize> {
+    let rest = frame.strip_prefix(prefix)?.strip_suffix(b")\n")?;
+    core::str::from_utf8(rest).ok()?.parse().ok()
+}
+
+/// Wait for a control frame whose decoded payload equals `bytes`, discarding any other frames.
+fn wait_for_bytes(rx: &Receiver<u8>, bytes: &[u8], timeout: Option<Duration>) -> Result<(), ()> {
+    loop {
+        match read_frame(rx, timeout) {
+            Ok(frame) if frame == bytes => return Ok(()),
+            Ok(_) => continue,
+            Err(_) => return Err(()),
+        }
+    }
+}
 
-    if i == bytes.len() {
-        Ok(())
-    } else {
-        Err(i)
+/// Read a single COBS control frame from the reader channel and decode it. Distinguishes a
+/// [`FrameError::Timeout`] (link still up) from a [`FrameError::Disconnected`] (reader thread gone)
+/// so callers can route a mid-transfer disconnect to the reconnect path.
+fn read_frame(rx: &Receiver<u8>, timeout: Option<Duration>) -> Result<Vec<u8>, FrameError> {
+    // The host only ever reads short control frames.
+    let mut scratch = vec![0u8; cobs::max_encoded_len(MAX_CONTROL_FRAME)];
+    let mut decoder = frame::FrameDecoder::new(&mut scratch);
+    let mut out = vec![0u8; MAX_CONTROL_FRAME];
+    loop {
+        let c = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => FrameError::Timeout,
+                RecvTimeoutError::Disconnected => FrameError::Disconnected,
+            })?,
+            None => rx.recv().map_err(|_| FrameError::Disconnected)?,
+        };
+        if let Ok(Some(n)) = decoder.push(c, &mut out) {
+            out.truncate(n);
+            return Ok(out);
+        }
     }
 }
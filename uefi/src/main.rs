@@ -5,14 +5,31 @@ extern crate alloc;
 
 use alloc::vec::Vec;
 use alloc::{format, vec};
-use core::fmt::Write;
 
 use log::{info, warn};
-use trebuchet_lib::CHUNK_SIZE;
+use trebuchet_lib::{cobs, frame, CHUNK_SIZE};
 use uefi::prelude::*;
 use uefi::proto::console::serial::Serial;
 use uefi::table::boot::{LoadImageSource, OpenProtocolAttributes, OpenProtocolParams};
 
+/// Overall budget of idle read polls before the serial transfer is abandoned for the fallback image.
+const RECEIVE_POLL_BUDGET: u64 = 50_000_000;
+
+/// Known-good recovery image compiled into the loader, booted when serial transfer fails.
+///
+/// The blob and its expected CRC are supplied by the build environment rather than committed to the
+/// tree: set `TREBUCHET_FALLBACK_IMAGE` to the path of the recovery `.efi` and
+/// `TREBUCHET_FALLBACK_CRC` to its X.25 checksum (decimal or `0x`-prefixed hex) when building with
+/// `--features fallback`.
+#[cfg(feature = "fallback")]
+mod fallback {
+    /// The recovery image blob embedded at build time.
+    pub static IMAGE: &[u8] = include_bytes!(env!("TREBUCHET_FALLBACK_IMAGE"));
+
+    /// Expected X.25 CRC of [`IMAGE`], verified before the recovery image is started.
+    pub static CRC: &str = env!("TREBUCHET_FALLBACK_CRC");
+}
+
 #[entry]
 fn main(_image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table).unwrap();
@@ -24,9 +41,12 @@ fn bootloader_main(boot_services: &BootServices) -> ! {
     info!("Trebuchet UEFI: the StoneOS UEFI chain-loader");
     {
         let image_buffer = receive_image(boot_services);
-        let image_source = LoadImageSource::FromBuffer {
-            buffer: &image_buffer,
-            file_path: None,
+        let image_source = match image_buffer.as_deref() {
+            Some(buffer) => LoadImageSource::FromBuffer {
+                buffer,
+                file_path: None,
+            },
+            None => fallback_image_source(),
         };
 
         info!("Loading received image.");
@@ -42,7 +62,36 @@ fn bootloader_main(boot_services: &BootServices) -> ! {
     unreachable!("Trebuchet UEFI: returned to chain loader after image start.")
 }
 
-fn receive_image(boot_services: &BootServices) -> Vec<u8> {
+/// Resolve the image source used when no valid image arrives over serial. With the `fallback`
+/// feature enabled this is the embedded recovery blob (after verifying its CRC); otherwise there is
+/// nothing known-good to boot.
+#[cfg(feature = "fallback")]
+fn fallback_image_source() -> LoadImageSource<'static> {
+    let expected = fallback::CRC
+        .strip_prefix("0x")
+        .map_or_else(
+            || fallback::CRC.parse::<u16>(),
+            |hex| u16::from_str_radix(hex, 16),
+        )
+        .expect("Invalid TREBUCHET_FALLBACK_CRC.");
+    assert_eq!(
+        trebuchet_lib::checksum(fallback::IMAGE),
+        expected,
+        "Embedded fallback image failed CRC verification."
+    );
+    warn!("Serial transfer failed; booting embedded fallback image.");
+    LoadImageSource::FromBuffer {
+        buffer: fallback::IMAGE,
+        file_path: None,
+    }
+}
+
+#[cfg(not(feature = "fallback"))]
+fn fallback_image_source() -> LoadImageSource<'static> {
+    panic!("Serial transfer failed and no fallback image is compiled in.");
+}
+
+fn receive_image(boot_services: &BootServices) -> Option<Vec<u8>> {
     info!("Loading image over serial.");
 
     info!("Opening serial communication.");
@@ -66,39 +115,146 @@ fn receive_image(boot_services: &BootServices) -> Vec<u8> {
     serial.reset().expect("Failed to reset the serial device.");
 
     info!("Requesting image.");
-    serial
-        .write_str(&format!("RDY({})\n", CHUNK_SIZE))
+    write_frame(&mut serial, format!("RDY({})\n", CHUNK_SIZE).as_bytes())
         .expect("Failed to send image request.");
 
-    let mut size_buffer = [0u8; core::mem::size_of::<u64>()];
-    receive_bytes(&mut serial, &mut size_buffer);
+    // Bound the whole transfer so a missing or stalled host eventually yields to the fallback image.
+    let mut budget = RECEIVE_POLL_BUDGET;
+
+    // The header carries the image size followed by the host's expected whole-image CRC.
+    let mut header_buffer = [0u8; core::mem::size_of::<u64>() + core::mem::size_of::<u16>()];
+    if !receive_bytes(&mut serial, &mut header_buffer, &mut budget) {
+        warn!("Timed out waiting for image size.");
+        return None;
+    }
 
-    let size_checksum = trebuchet_lib::checksum(&size_buffer);
-    serial
-        .write_str(&format!("OK({})\n", size_checksum))
+    let header_checksum = trebuchet_lib::checksum(&header_buffer);
+    write_frame(&mut serial, format!("OK({})\n", header_checksum).as_bytes())
         .unwrap_or_else(|_| warn!("Failed to send image size confirmation."));
 
+    let mut size_buffer = [0u8; core::mem::size_of::<u64>()];
+    size_buffer.copy_from_slice(&header_buffer[..core::mem::size_of::<u64>()]);
     let size = usize::from_be_bytes(size_buffer);
+    let expected_checksum =
+        u16::from_be_bytes([header_buffer[core::mem::size_of::<u64>()], header_buffer[core::mem::size_of::<u64>() + 1]]);
     info!("Expected image size: {} bytes.", size);
 
     info!("Waiting for image.");
     let mut image_buffer = vec![0u8; size];
-    receive_bytes(&mut serial, &mut image_buffer);
+    if !receive_bytes(&mut serial, &mut image_buffer, &mut budget) {
+        warn!("Timed out waiting for image.");
+        return None;
+    }
 
     let image_checksum = trebuchet_lib::checksum(&image_buffer);
-    serial
-        .write_str(&format!("OK({})\n", image_checksum))
+    write_frame(&mut serial, format!("OK({})\n", image_checksum).as_bytes())
         .unwrap_or_else(|_| warn!("Failed to send image receipt confirmation."));
 
+    // Fall back to the known-good image if the assembled image doesn't match the expected CRC.
+    if image_checksum != expected_checksum {
+        warn!("Received image failed checksum comparison.");
+        return None;
+    }
+
     info!("Received image.");
-    image_buffer
+    Some(image_buffer)
+}
+
+/// Receive `data` as a stream of indexed, CRC-checked chunk frames, storing each at its own offset
+/// and emitting cumulative `ACK(i)` frames for the highest in-order index received. Retransmissions
+/// of already-stored chunks are idempotent. Returns `false` if the shared poll `budget` is exhausted
+/// before every chunk arrives.
+fn receive_bytes(serial: &mut Serial, data: &mut [u8], budget: &mut u64) -> bool {
+    let header = core::mem::size_of::<u32>();
+    let crc_len = core::mem::size_of::<u16>();
+    let chunk_count = data.len().div_ceil(CHUNK_SIZE);
+    // The index header and CRC precede and trail the largest possible payload.
+    let mut frame = vec![0u8; header + CHUNK_SIZE + crc_len];
+
+    // Highest index `expected` such that chunks `0..expected` have all been received in order.
+    let mut expected = 0;
+    while expected < chunk_count {
+        let n = match read_frame(serial, &mut frame, budget) {
+            Some(n) => n,
+            None => return false,
+        };
+
+        if let Some(index) = accept_chunk(data, &frame[..n], chunk_count) {
+            // A valid frame whose index matches the in-order frontier advances it.
+            if index == expected {
+                expected += 1;
+            }
+        }
+
+        let reply = if expected > 0 {
+            format!("ACK({})\n", expected - 1)
+        } else {
+            format!("NAK({})\n", 0)
+        };
+        write_frame(serial, reply.as_bytes())
+            .unwrap_or_else(|_| warn!("Failed to send chunk acknowledgement."));
+    }
+    true
+}
+
+/// Validate an indexed chunk frame (`[index][payload][crc]`) and, if sound, store its payload at the
+/// chunk's offset in `data`. Returns the chunk index on success, `None` on a malformed frame.
+fn accept_chunk(data: &mut [u8], frame: &[u8], chunk_count: usize) -> Option<usize> {
+    let header = core::mem::size_of::<u32>();
+    let crc_len = core::mem::size_of::<u16>();
+    if frame.len() < header + crc_len {
+        return None;
+    }
+
+    let (body, crc) = frame.split_at(frame.len() - crc_len);
+    let crc = u16::from_be_bytes([crc[0], crc[1]]);
+    if trebuchet_lib::checksum(body) != crc {
+        return None;
+    }
+
+    let index = u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize;
+    if index >= chunk_count {
+        return None;
+    }
+
+    let offset = index * CHUNK_SIZE;
+    let len = (data.len() - offset).min(CHUNK_SIZE);
+    let payload = &body[header..];
+    if payload.len() != len {
+        return None;
+    }
+
+    data[offset..offset + len].copy_from_slice(payload);
+    Some(index)
+}
+
+/// COBS-encode `payload` (appending the `0x00` delimiter) and write the frame to the serial device.
+fn write_frame(serial: &mut Serial, payload: &[u8]) -> uefi::Result<(), usize> {
+    let mut encoded = vec![0u8; cobs::max_encoded_len(payload.len())];
+    let n = cobs::encode(payload, &mut encoded);
+    serial.write(&encoded[..n])
 }
 
-fn receive_bytes(serial: &mut Serial, data: &mut [u8]) {
-    for (i, chunk) in data.chunks_mut(CHUNK_SIZE).enumerate() {
-        while serial.read(chunk).is_err() {}
-        serial
-            .write_str(&format!("ACK({})\n", i))
-            .unwrap_or_else(|_| warn!("Failed to send byte receipt confirmation."));
+/// Read a single COBS frame (terminated by a `0x00` delimiter) a byte at a time and decode it into
+/// `out`, returning the number of decoded bytes (`0` on a malformed frame), or `None` once the
+/// shared poll `budget` is exhausted.
+fn read_frame(serial: &mut Serial, out: &mut [u8], budget: &mut u64) -> Option<usize> {
+    let mut scratch = vec![0u8; cobs::max_encoded_len(out.len())];
+    let mut decoder = frame::FrameDecoder::new(&mut scratch);
+    loop {
+        let mut c = 0u8;
+        while serial.read(core::slice::from_mut(&mut c)).is_err() {
+            if *budget == 0 {
+                return None;
+            }
+            *budget -= 1;
+        }
+        match decoder.push(c, out) {
+            Ok(Some(n)) => return Some(n),
+            // A malformed frame is reported as an invalid (zero-length) chunk so the caller rejects it
+            // and repeats its current reply.
+            Err(_) => return Some(0),
+            Ok(None) => (),
+        }
     }
 }